@@ -1,41 +1,87 @@
 //! Utility functions for calculating unlock schedules for a vesting account.
 
 use crate::Vesting;
+use std::fmt;
 
-pub fn available_for_withdrawal(vesting: &Vesting, current_ts: i64) -> u64 {
-    std::cmp::min(outstanding_vested(vesting, current_ts), balance(vesting))
+/// Errors produced while computing balances for a (possibly malformed)
+/// `Vesting` account. Callers map these to their own on-chain error codes
+/// rather than the calculator unwrapping and aborting the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VestingError {
+    /// A checked subtraction underflowed, e.g. `whitelist_owned` exceeded
+    /// `outstanding`, or `outstanding` exceeded `start_balance`.
+    Underflow,
+    /// A checked operation overflowed.
+    Overflow,
+}
+
+impl fmt::Display for VestingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VestingError::Underflow => write!(f, "vesting account arithmetic underflowed"),
+            VestingError::Overflow => write!(f, "vesting account arithmetic overflowed"),
+        }
+    }
+}
+
+impl std::error::Error for VestingError {}
+
+pub fn available_for_withdrawal(vesting: &Vesting, current_ts: i64) -> Result<u64, VestingError> {
+    Ok(std::cmp::min(
+        outstanding_vested(vesting, current_ts)?,
+        balance(vesting)?,
+    ))
 }
 
 // The amount of funds currently in the vault.
-fn balance(vesting: &Vesting) -> u64 {
+fn balance(vesting: &Vesting) -> Result<u64, VestingError> {
     vesting
         .outstanding
         .checked_sub(vesting.whitelist_owned)
-        .unwrap()
+        .ok_or(VestingError::Underflow)
 }
 
 // The amount of outstanding locked tokens vested. Note that these
 // tokens might have been transferred to whitelisted programs.
-fn outstanding_vested(vesting: &Vesting, current_ts: i64) -> u64 {
+fn outstanding_vested(vesting: &Vesting, current_ts: i64) -> Result<u64, VestingError> {
     total_vested(vesting, current_ts)
-        .checked_sub(withdrawn_amount(vesting))
-        .unwrap()
+        .checked_sub(withdrawn_amount(vesting)?)
+        .ok_or(VestingError::Underflow)
 }
 
 // Returns the amount withdrawn from this vesting account.
-fn withdrawn_amount(vesting: &Vesting) -> u64 {
+fn withdrawn_amount(vesting: &Vesting) -> Result<u64, VestingError> {
     vesting
         .start_balance
         .checked_sub(vesting.outstanding)
-        .unwrap()
+        .ok_or(VestingError::Underflow)
 }
 
 // Returns the total vested amount up to the given ts, assuming zero
 // withdrawals and zero funds sent to other programs.
+//
+// If `vesting.schedule` is set, it takes priority and is evaluated as an
+// arbitrary piecewise schedule (see `Schedule`). Otherwise we fall back to
+// the uniform linear ramp described by `vesting`'s flat fields.
+//
+// If `vesting.cliff_ts` is set, nothing vests before it. Periods are still
+// counted from `start_ts`, not `cliff_ts`, so crossing the cliff releases
+// whatever periods have accumulated since `start_ts` all at once, rather
+// than resetting the schedule to start counting from the cliff.
 fn total_vested(vesting: &Vesting, current_ts: i64) -> u64 {
+    if let Some(schedule) = &vesting.schedule {
+        return schedule.total_vested(current_ts);
+    }
+
     if current_ts < vesting.start_ts {
-        0
-    } else if current_ts >= vesting.end_ts {
+        return 0;
+    }
+    if let Some(cliff_ts) = vesting.cliff_ts {
+        if current_ts < cliff_ts {
+            return 0;
+        }
+    }
+    if current_ts >= vesting.end_ts {
         vesting.start_balance
     } else {
         linear_unlock(vesting, current_ts)
@@ -43,26 +89,130 @@ fn total_vested(vesting: &Vesting, current_ts: i64) -> u64 {
 }
 
 // Assumes `current_ts` < `vesting.end_ts`.
+//
+// All math here is done in u128 integer arithmetic rather than floats.
+// On-chain execution must be bit-for-bit deterministic across validators,
+// and f64 division is not guaranteed to be, so we avoid it entirely.
 fn linear_unlock(vesting: &Vesting, current_ts: i64) -> u64 {
-    // Signed division not supported.
-    let current_ts = current_ts as f64;
-    let start_ts = vesting.start_ts as f64;
-    let end_ts = vesting.end_ts as f64;
+    // The period current_ts falls in (floor divides), computed without ever
+    // materializing a fractional period length.
+    // Invariant: current_ts >= start_ts, so this is non-negative.
+    let current_period = (current_ts - vesting.start_ts) as u128 * vesting.period_count as u128
+        / (vesting.end_ts - vesting.start_ts) as u128;
 
-    // The length of a single vesting period.
-    // Invariant: period_count <= (end_ts - start_ts).
-    let period_secs: f64 = (end_ts - start_ts) / (vesting.period_count as f64);
+    // Rounds the total reward down to the nearest integer, since we can't
+    // pay out fractional rewards.
+    // Invariant: current_ts < end_ts, so current_period <= period_count and
+    // the cast back to u64 below cannot overflow.
+    let vested = current_period * vesting.start_balance as u128 / vesting.period_count as u128;
 
-    // The period the current_ts is in (floor divides).
-    // Invariant: current_ts >= start_ts.
-    let current_period: u64 = ((current_ts - start_ts) / period_secs) as u64;
+    vested as u64
+}
 
-    // Reward per period.
-    let reward_per_period: f64 = (vesting.start_balance as f64) / (vesting.period_count as f64);
+/// A single point in a piecewise unlock schedule: as of `unlock_ts`,
+/// `cumulative_amount` of the grant's `start_balance` has vested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub unlock_ts: i64,
+    pub cumulative_amount: u64,
+}
 
-    // Rounds the total reward down to the nearest integer, since we can't
-    // pay out fractional rewards.
-    ((current_period as f64) * reward_per_period) as u64
+/// An arbitrary, piecewise unlock schedule, expressed as a list of
+/// checkpoints sorted by ascending `unlock_ts`. Supports both step-function
+/// schedules (e.g. a cliff-only grant, where nothing interpolates between
+/// checkpoints) and schedules that vest linearly between checkpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    pub checkpoints: Vec<Checkpoint>,
+    pub interpolate: bool,
+}
+
+impl Schedule {
+    /// The convenience constructor for the case `linear_unlock` already
+    /// handles: a single ramp of `period_count` equal-sized periods between
+    /// `start_ts` and `end_ts`, optionally preceded by a cliff at
+    /// `cliff_ts` (see `total_vested`'s cliff semantics). Expands that ramp
+    /// into one checkpoint per period boundary so it can be evaluated by
+    /// the same piecewise path as any other schedule.
+    pub fn from_linear(
+        start_balance: u64,
+        start_ts: i64,
+        end_ts: i64,
+        period_count: u64,
+        cliff_ts: Option<i64>,
+    ) -> Schedule {
+        let mut checkpoints = Vec::with_capacity(period_count as usize + 1);
+        checkpoints.push(Checkpoint {
+            unlock_ts: start_ts,
+            cumulative_amount: 0,
+        });
+        for period in 1..=period_count {
+            // Ceiling division: linear_unlock only crosses into period `k`
+            // once `k * window / period_count` seconds have elapsed, so the
+            // boundary is the smallest ts satisfying that, not the largest
+            // ts still short of it. Done in u128 for the same overflow
+            // reasons as cumulative_amount below.
+            let unlock_ts = start_ts
+                + ((end_ts - start_ts) as u128 * period as u128)
+                    .div_ceil(period_count as u128) as i64;
+            let cumulative_amount =
+                (period as u128 * start_balance as u128 / period_count as u128) as u64;
+            checkpoints.push(Checkpoint {
+                unlock_ts,
+                cumulative_amount,
+            });
+        }
+        if let Some(cliff_ts) = cliff_ts {
+            // Everything that would have unlocked before the cliff instead
+            // releases all at once, at the cliff. Collapse the checkpoints
+            // this pulls forward into a single one (keeping the highest
+            // cumulative_amount among them) so unlock_ts stays strictly
+            // increasing.
+            for checkpoint in checkpoints.iter_mut() {
+                if checkpoint.unlock_ts < cliff_ts {
+                    checkpoint.unlock_ts = cliff_ts;
+                }
+            }
+            checkpoints.dedup_by(|a, b| {
+                if a.unlock_ts == b.unlock_ts {
+                    b.cumulative_amount = a.cumulative_amount;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        Schedule {
+            checkpoints,
+            interpolate: false,
+        }
+    }
+
+    // Finds the last checkpoint whose unlock_ts <= current_ts and returns
+    // its cumulative amount, interpolating linearly toward the next
+    // checkpoint if `self.interpolate` is set.
+    fn total_vested(&self, current_ts: i64) -> u64 {
+        let idx = match self
+            .checkpoints
+            .partition_point(|c| c.unlock_ts <= current_ts)
+        {
+            0 => return 0, // Before the first checkpoint.
+            n => n - 1,
+        };
+        let checkpoint = self.checkpoints[idx];
+        if !self.interpolate {
+            return checkpoint.cumulative_amount;
+        }
+        match self.checkpoints.get(idx + 1) {
+            None => checkpoint.cumulative_amount,
+            Some(next) => {
+                let span = (next.unlock_ts - checkpoint.unlock_ts) as u128;
+                let elapsed = (current_ts - checkpoint.unlock_ts) as u128;
+                let delta = (next.cumulative_amount - checkpoint.cumulative_amount) as u128;
+                checkpoint.cumulative_amount + (elapsed * delta / span) as u64
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -145,6 +295,57 @@ mod tests {
         run_test(v, cases);
     }
 
+    // Window = 70 seconds, cliff at 50 seconds in.
+    // Period count = 10 (7 seconds each).
+    // =>
+    // Nothing vests until the cliff, at which point the periods that would
+    // have vested since start_ts (7 of them) all release at once.
+    #[test]
+    fn cliff() {
+        let v = create_vesting_with_cliff(100, 0, 50, 70, 10);
+        let cases = vec![
+            [0, 0],   // Entirely before the cliff.
+            [20, 0],  // Still before the cliff.
+            [49, 0],  // One second before the cliff.
+            [50, 70], // At the cliff: 7 periods release at once.
+            [56, 80], // One more period has elapsed since start_ts.
+            [63, 90], // And another.
+            [70, 100], // All vested.
+            [71, 100],
+        ];
+        run_test(v, cases);
+    }
+
+    // A malformed account where whitelist_owned exceeds outstanding should
+    // surface an error rather than panicking.
+    #[test]
+    fn balance_underflow_is_an_error() {
+        let mut v = create_vesting(5, 10, 20, 2);
+        v.outstanding = 1;
+        v.whitelist_owned = 2;
+        assert_eq!(balance(&v), Err(VestingError::Underflow));
+    }
+
+    // A malformed account where outstanding exceeds start_balance should
+    // surface an error rather than panicking.
+    #[test]
+    fn withdrawn_amount_underflow_is_an_error() {
+        let mut v = create_vesting(5, 10, 20, 2);
+        v.outstanding = 6;
+        assert_eq!(withdrawn_amount(&v), Err(VestingError::Underflow));
+    }
+
+    #[test]
+    fn available_for_withdrawal_propagates_underflow() {
+        let mut v = create_vesting(5, 10, 20, 2);
+        v.outstanding = 1;
+        v.whitelist_owned = 2;
+        assert_eq!(
+            available_for_withdrawal(&v, 20),
+            Err(VestingError::Underflow)
+        );
+    }
+
     // Each case is an array consisting of
     // [start_balance, start_ts, end_ts, period_count, current_ts, total_vested].
     fn run_test(v: Vesting, cases: Vec<[u64; 2]>) {
@@ -160,6 +361,26 @@ mod tests {
         start_ts: i64,
         end_ts: i64,
         period_count: u64,
+    ) -> Vesting {
+        create_vesting_full(start_balance, start_ts, None, end_ts, period_count)
+    }
+
+    fn create_vesting_with_cliff(
+        start_balance: u64,
+        start_ts: i64,
+        cliff_ts: i64,
+        end_ts: i64,
+        period_count: u64,
+    ) -> Vesting {
+        create_vesting_full(start_balance, start_ts, Some(cliff_ts), end_ts, period_count)
+    }
+
+    fn create_vesting_full(
+        start_balance: u64,
+        start_ts: i64,
+        cliff_ts: Option<i64>,
+        end_ts: i64,
+        period_count: u64,
     ) -> Vesting {
         Vesting {
             beneficiary: Pubkey::new_unique(),
@@ -170,11 +391,137 @@ mod tests {
             start_balance,
             created_ts: 0,
             start_ts,
+            cliff_ts,
             end_ts,
             period_count,
             whitelist_owned: 0,
             nonce: 0,
             realizor: None,
+            schedule: None,
+        }
+    }
+
+    fn create_vesting_with_schedule(start_balance: u64, schedule: Schedule) -> Vesting {
+        Vesting {
+            schedule: Some(schedule),
+            ..create_vesting(start_balance, 0, 0, 1)
+        }
+    }
+
+    // An irregular, front-loaded schedule: 60% at the one-week mark, then
+    // two uneven top-ups.
+    #[test]
+    fn irregular_front_loaded_schedule() {
+        let schedule = Schedule {
+            checkpoints: vec![
+                Checkpoint {
+                    unlock_ts: 0,
+                    cumulative_amount: 0,
+                },
+                Checkpoint {
+                    unlock_ts: 604_800,
+                    cumulative_amount: 60,
+                },
+                Checkpoint {
+                    unlock_ts: 1_000_000,
+                    cumulative_amount: 90,
+                },
+                Checkpoint {
+                    unlock_ts: 2_000_000,
+                    cumulative_amount: 100,
+                },
+            ],
+            interpolate: false,
+        };
+        let v = create_vesting_with_schedule(100, schedule);
+        let cases = vec![
+            [0, 0],
+            [604_799, 0],
+            [604_800, 60], // Cliff-like jump to 60%.
+            [999_999, 60],
+            [1_000_000, 90],
+            [1_999_999, 90],
+            [2_000_000, 100],
+            [3_000_000, 100],
+        ];
+        run_test(v, cases);
+    }
+
+    // A step-function, cliff-only schedule: nothing, then everything, with
+    // no intermediate periods at all.
+    #[test]
+    fn step_function_cliff_only_schedule() {
+        let schedule = Schedule {
+            checkpoints: vec![
+                Checkpoint {
+                    unlock_ts: 0,
+                    cumulative_amount: 0,
+                },
+                Checkpoint {
+                    unlock_ts: 100,
+                    cumulative_amount: 50,
+                },
+            ],
+            interpolate: false,
+        };
+        let v = create_vesting_with_schedule(50, schedule);
+        let cases = vec![[0, 0], [50, 0], [99, 0], [100, 50], [200, 50]];
+        run_test(v, cases);
+    }
+
+    // The same checkpoints as above, but with interpolation turned on:
+    // vesting now ramps linearly between checkpoints instead of jumping.
+    #[test]
+    fn interpolated_schedule() {
+        let schedule = Schedule {
+            checkpoints: vec![
+                Checkpoint {
+                    unlock_ts: 0,
+                    cumulative_amount: 0,
+                },
+                Checkpoint {
+                    unlock_ts: 100,
+                    cumulative_amount: 50,
+                },
+            ],
+            interpolate: true,
+        };
+        let v = create_vesting_with_schedule(50, schedule);
+        let cases = vec![[0, 0], [50, 25], [75, 37], [100, 50], [200, 50]];
+        run_test(v, cases);
+    }
+
+    // `Schedule::from_linear` should reproduce the plain linear_unlock path
+    // exactly, including its cliff semantics.
+    #[test]
+    fn from_linear_matches_the_flat_field_path() {
+        let flat = create_vesting_with_cliff(100, 0, 50, 70, 10);
+        let expanded =
+            create_vesting_with_schedule(100, Schedule::from_linear(100, 0, 70, 10, Some(50)));
+        for current_ts in 0..=71 {
+            assert_eq!(
+                total_vested(&flat, current_ts),
+                total_vested(&expanded, current_ts),
+                "mismatch at ts={}",
+                current_ts
+            );
+        }
+    }
+
+    // Same equivalence check, but over a window that isn't evenly divisible
+    // by period_count, which is what makes the period-boundary ceiling
+    // division in `from_linear` actually load-bearing.
+    #[test]
+    fn from_linear_matches_the_flat_field_path_when_not_evenly_divisible() {
+        let flat = create_vesting(5, 10, 21, 2);
+        let expanded = create_vesting_with_schedule(5, Schedule::from_linear(5, 10, 21, 2, None));
+        for current_ts in 0..=22 {
+            assert_eq!(
+                total_vested(&flat, current_ts),
+                total_vested(&expanded, current_ts),
+                "mismatch at ts={}",
+                current_ts
+            );
         }
     }
 }